@@ -1,13 +1,150 @@
+use alloc::vec::Vec;
 use core::result::Result;
 
 use crate::error::Error;
 
 use ckb_std::{ckb_constants::Source, ckb_types::prelude::*, high_level::*};
 
+// Accumulated rate at CKB genesis, used as the reference point for iCKB's standard value.
+pub const DEFAULT_ACCUMULATED_RATE: u64 = 10_000_000_000_000_000;
+
+// Given a deposit and the accumulated rates of the block it was created in and of the block it
+// is being withdrawn in, returns the maximum capacity redeemable from the Nervos DAO.
+// The deposit's occupied capacity never earns interest, so only the remainder is scaled by the
+// ratio of the two accumulated rates.
+pub fn maximum_withdraw(
+    deposit_index: usize,
+    deposit_source: Source,
+    deposit_header_index: usize,
+    deposit_header_source: Source,
+    withdraw_header_index: usize,
+    withdraw_header_source: Source,
+) -> Result<u64, Error> {
+    let occupied_capacity = load_cell_occupied_capacity(deposit_index, deposit_source)?;
+    let total_capacity = load_cell_capacity(deposit_index, deposit_source)?;
+
+    let deposit_ar = extract_accumulated_rate(deposit_header_index, deposit_header_source)?;
+    let withdraw_ar = extract_accumulated_rate(withdraw_header_index, withdraw_header_source)?;
+
+    let interest = scale_by_accumulated_rate(
+        total_capacity - occupied_capacity,
+        deposit_ar,
+        withdraw_ar,
+    )?;
+
+    occupied_capacity
+        .checked_add(interest)
+        .ok_or(Error::Encoding)
+}
+
+// Scales an unoccupied capacity from the deposit's accumulated rate to the withdrawing
+// block's accumulated rate.
+fn scale_by_accumulated_rate(
+    unoccupied_capacity: u64,
+    deposit_ar: u64,
+    withdraw_ar: u64,
+) -> Result<u64, Error> {
+    mul_div(unoccupied_capacity, withdraw_ar, deposit_ar)
+}
+
+// iCKB charges a fixed minting fee on top of the standard accumulated-rate conversion,
+// expressed as a fraction MINTING_FEE_NUMERATOR / MINTING_FEE_DENOMINATOR of the minted amount.
+pub const MINTING_FEE_NUMERATOR: u64 = 1;
+pub const MINTING_FEE_DENOMINATOR: u64 = 1_000;
+
+// Converts a deposit's unoccupied capacity into the amount of iCKB tokens it is worth,
+// normalizing against the genesis accumulated rate and deducting the standard minting fee.
+pub fn deposit_to_ickb(unoccupied_capacity: u64, deposit_accumulated_rate: u64) -> Result<u64, Error> {
+    let tokens = scale_by_accumulated_rate(
+        unoccupied_capacity,
+        deposit_accumulated_rate,
+        DEFAULT_ACCUMULATED_RATE,
+    )?;
+
+    let fee = fraction(tokens, MINTING_FEE_NUMERATOR, MINTING_FEE_DENOMINATOR)?;
+
+    tokens.checked_sub(fee).ok_or(Error::Encoding)
+}
+
+// Approximate inverse of `deposit_to_ickb`, for sizing a deposit meant to mint roughly
+// `token_amount` iCKB: recovers the unoccupied capacity that amount of tokens was minted from,
+// against a deposit with the given accumulated rate.
+// The minting fee in `deposit_to_ickb` truncates towards zero, so this round-trips only up to
+// that truncation; it is not exact and must not be used to validate a cell's `token_amount`
+// field. For that, recompute `deposit_to_ickb` on the deposit's own capacity and compare for
+// equality — see `ickb_amount_matches_deposit`.
+pub fn ickb_to_deposit(token_amount: u64, deposit_accumulated_rate: u64) -> Result<u64, Error> {
+    let tokens_before_fee = (token_amount as u128) * (MINTING_FEE_DENOMINATOR as u128)
+        / ((MINTING_FEE_DENOMINATOR - MINTING_FEE_NUMERATOR) as u128);
+    let tokens_before_fee = u64::try_from(tokens_before_fee).map_err(|_| Error::Encoding)?;
+
+    scale_by_accumulated_rate(
+        tokens_before_fee,
+        DEFAULT_ACCUMULATED_RATE,
+        deposit_accumulated_rate,
+    )
+}
+
+// Checks that a cell's `token_amount` field matches what its deposit is actually worth,
+// by recomputing `deposit_to_ickb` forward rather than round-tripping through
+// `ickb_to_deposit`, which avoids the fee-truncation drift that would make an exact
+// comparison unreliable.
+pub fn ickb_amount_matches_deposit(
+    token_amount: u64,
+    unoccupied_capacity: u64,
+    deposit_accumulated_rate: u64,
+) -> Result<bool, Error> {
+    Ok(deposit_to_ickb(unoccupied_capacity, deposit_accumulated_rate)? == token_amount)
+}
+
+fn fraction(amount: u64, numerator: u64, denominator: u64) -> Result<u64, Error> {
+    mul_div(amount, numerator, denominator)
+}
+
+// Computes `a * b / c` through a u128 intermediate, to avoid overflow, failing if `c` is zero
+// or the result doesn't fit back in a u64.
+fn mul_div(a: u64, b: u64, c: u64) -> Result<u64, Error> {
+    if c == 0 {
+        return Err(Error::Encoding);
+    }
+
+    let scaled = (a as u128) * (b as u128) / (c as u128);
+
+    u64::try_from(scaled).map_err(|_| Error::Encoding)
+}
+
+// The present fixed cell-data layout, identified by a leading version byte set to zero.
+const ICKB_DATA_VERSION_0: u8 = 0;
+
+// Length, in bytes, of the legacy layout that predates the version byte.
+const LEGACY_ICKB_DATA_LEN: usize = 16;
+
+// Cell data is versioned behind a leading byte so the layout can grow without breaking
+// existing parsers. A leading byte that is not a recognized version tag is read as the legacy
+// layout when the data is at least `LEGACY_ICKB_DATA_LEN` bytes long, and rejected with
+// `Error::Encoding` otherwise: an unknown version of that length is not rejected.
+// Cells encoded before the version byte existed carry no such byte and are at least
+// `LEGACY_ICKB_DATA_LEN` bytes long (trailing bytes tolerated, as the original parser did).
+// A legacy cell's leading byte is an arbitrary token-amount byte, so it can coincide with a
+// real version tag; recognized version tags are therefore tried first and always win, and
+// the length-based legacy fallback only ever catches the remaining, unrecognized bytes. This
+// keeps a future version safe to add even if its encoding happens to total
+// `LEGACY_ICKB_DATA_LEN` bytes — dispatch never falls back to "legacy" for a leading byte this
+// parser actually recognizes as a version.
 pub fn extract_ickb_data(index: usize, source: Source) -> Result<(u64, u64, u8), Error> {
     let ickb_data = load_cell_data(index, source)?;
 
-    if ickb_data.len() < 16 {
+    match ickb_data.first() {
+        Some(&ICKB_DATA_VERSION_0) => extract_ickb_data_v0(&ickb_data[1..]),
+        // Multi-group receipt cells carry no token amount; see `extract_ickb_receipts`.
+        Some(&ICKB_DATA_VERSION_1) => Err(Error::Encoding),
+        _ if ickb_data.len() >= LEGACY_ICKB_DATA_LEN => extract_ickb_data_v0(&ickb_data),
+        _ => Err(Error::Encoding),
+    }
+}
+
+fn extract_ickb_data_v0(ickb_data: &[u8]) -> Result<(u64, u64, u8), Error> {
+    if ickb_data.len() < LEGACY_ICKB_DATA_LEN {
         return Err(Error::Encoding);
     }
 
@@ -28,6 +165,19 @@ pub fn extract_ickb_data(index: usize, source: Source) -> Result<(u64, u64, u8),
     Ok((token_amount, receipt_amount, receipt_count))
 }
 
+// Encodes the current (version 0) cell-data layout: a leading version byte followed by the
+// token amount, the single deposit amount and the contiguous-deposit count.
+pub fn encode_ickb_data(token_amount: u64, receipt_amount: u64, receipt_count: u8) -> Vec<u8> {
+    let mut ickb_data = Vec::with_capacity(17);
+
+    ickb_data.push(ICKB_DATA_VERSION_0);
+    ickb_data.extend_from_slice(&token_amount.to_le_bytes());
+    ickb_data.extend_from_slice(&receipt_amount.to_le_bytes()[0..7]);
+    ickb_data.push(receipt_count);
+
+    ickb_data
+}
+
 pub fn extract_unused_capacity(index: usize, source: Source) -> Result<u64, Error> {
     Ok(load_cell_capacity(index, source)? - load_cell_occupied_capacity(index, source)?)
 }
@@ -65,3 +215,229 @@ fn u64_from(data: &[u8], begin: usize) -> Result<u64, Error> {
 
     Ok(number)
 }
+
+// Golomb-Rice parameter used to code deposit counts in a multi-group receipt. Small counts
+// dominate in practice, so a small k keeps the quotient's unary prefix short.
+const RECEIPT_GOLOMB_RICE_K: u32 = 4;
+
+// Multi-group bit-packed receipt format, dispatched through the same leading version byte as
+// `extract_ickb_data` rather than as a second, unversioned encoding space.
+const ICKB_DATA_VERSION_1: u8 = 1;
+
+// Decodes a receipt's (deposit amount, deposit count) groups.
+// Version 1 cells hold a bit-packed stream: a varint group count, then per group a
+// Golomb-Rice-coded count and a delta-varint-coded amount, letting a receipt describe several
+// runs of heterogeneous deposits instead of a single run. Version 0 and legacy (unversioned)
+// cells hold the single-run layout read by `extract_ickb_data`, reported here as one group.
+pub fn extract_ickb_receipts(index: usize, source: Source) -> Result<Vec<(u64, u64)>, Error> {
+    let ickb_data = load_cell_data(index, source)?;
+
+    match ickb_data.first() {
+        Some(&ICKB_DATA_VERSION_1) => extract_ickb_receipts_v1(&ickb_data[1..]),
+        Some(&ICKB_DATA_VERSION_0) => single_run_receipt(&ickb_data[1..]),
+        _ if ickb_data.len() >= LEGACY_ICKB_DATA_LEN => single_run_receipt(&ickb_data),
+        _ => Err(Error::Encoding),
+    }
+}
+
+fn single_run_receipt(ickb_data: &[u8]) -> Result<Vec<(u64, u64)>, Error> {
+    let (_, receipt_amount, receipt_count) = extract_ickb_data_v0(ickb_data)?;
+
+    let mut groups = Vec::new();
+    groups.push((receipt_amount, receipt_count as u64));
+
+    Ok(groups)
+}
+
+fn extract_ickb_receipts_v1(ickb_data: &[u8]) -> Result<Vec<(u64, u64)>, Error> {
+    let mut reader = BitStreamReader::new(ickb_data);
+
+    // `group_count` comes straight off the untrusted stream, so it must not size an
+    // allocation: each group consumes at least one bit, and the loop below already bails
+    // out with `Error::Encoding` on truncation.
+    let group_count = read_varint(&mut reader)?;
+    let mut groups = Vec::new();
+
+    let mut amount = 0u64;
+    for _ in 0..group_count {
+        let count = golomb_rice_decode(&mut reader, RECEIPT_GOLOMB_RICE_K)?;
+        let delta = read_varint(&mut reader)?;
+        amount = amount.checked_add(delta).ok_or(Error::Encoding)?;
+
+        groups.push((amount, count));
+    }
+
+    Ok(groups)
+}
+
+// Encodes a receipt's (deposit amount, deposit count) groups as a version 1 cell: a leading
+// version byte followed by the bit-packed stream read by `extract_ickb_receipts_v1`. Groups
+// must be given in non-decreasing amount order, matching the delta-varint decoding above.
+pub fn encode_ickb_receipts(groups: &[(u64, u64)]) -> Result<Vec<u8>, Error> {
+    let mut writer = BitStreamWriter::new();
+    write_varint(&mut writer, groups.len() as u64);
+
+    let mut previous_amount = 0u64;
+    for &(amount, count) in groups {
+        golomb_rice_encode(&mut writer, count, RECEIPT_GOLOMB_RICE_K);
+
+        let delta = amount.checked_sub(previous_amount).ok_or(Error::Encoding)?;
+        write_varint(&mut writer, delta);
+        previous_amount = amount;
+    }
+
+    let mut ickb_data = Vec::new();
+    ickb_data.push(ICKB_DATA_VERSION_1);
+    ickb_data.extend_from_slice(&writer.finish());
+
+    Ok(ickb_data)
+}
+
+// Reads a little-endian base-128 varint: each byte holds 7 value bits with its top bit set
+// when another byte follows.
+fn read_varint(reader: &mut BitStreamReader) -> Result<u64, Error> {
+    let mut value = 0u64;
+    let mut shift = 0u32;
+
+    loop {
+        let byte = reader.read(8)?;
+        value |= (byte & 0x7f) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+
+        shift += 7;
+        if shift >= 64 {
+            return Err(Error::Encoding);
+        }
+    }
+}
+
+// Writes a little-endian base-128 varint, the inverse of `read_varint`.
+fn write_varint(writer: &mut BitStreamWriter, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            writer.write(byte as u64, 8);
+            return;
+        }
+
+        writer.write((byte | 0x80) as u64, 8);
+    }
+}
+
+// Decodes a Golomb-Rice coded non-negative integer with remainder width `k`: a unary-coded
+// quotient (`q` one-bits terminated by a zero bit) followed by the `k`-bit remainder, so that
+// `n = (q << k) | r`.
+fn golomb_rice_decode(reader: &mut BitStreamReader, k: u32) -> Result<u64, Error> {
+    let mut quotient = 0u64;
+    while reader.read(1)? == 1 {
+        quotient = quotient.checked_add(1).ok_or(Error::Encoding)?;
+    }
+
+    let remainder = if k == 0 { 0 } else { reader.read(k)? };
+
+    Ok((quotient << k) | remainder)
+}
+
+// Encodes a non-negative integer as a Golomb-Rice code with remainder width `k`, the inverse
+// of `golomb_rice_decode`.
+fn golomb_rice_encode(writer: &mut BitStreamWriter, n: u64, k: u32) {
+    let quotient = n >> k;
+    for _ in 0..quotient {
+        writer.write(1, 1);
+    }
+    writer.write(0, 1);
+
+    if k > 0 {
+        let remainder = n & ((1u64 << k) - 1);
+        writer.write(remainder, k);
+    }
+}
+
+// Reads bits MSB-first out of a byte slice, one byte at a time.
+struct BitStreamReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u8,
+    bit_offset: u8,
+}
+
+impl<'a> BitStreamReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            buffer: 0,
+            bit_offset: 8,
+        }
+    }
+
+    // Pulls the requested number of bits (at most 64) out of the stream, refilling the
+    // one-byte buffer from the underlying slice as needed.
+    fn read(&mut self, bits: u32) -> Result<u64, Error> {
+        let mut value = 0u64;
+
+        for _ in 0..bits {
+            if self.bit_offset == 8 {
+                if self.pos >= self.data.len() {
+                    return Err(Error::Encoding);
+                }
+                self.buffer = self.data[self.pos];
+                self.pos += 1;
+                self.bit_offset = 0;
+            }
+
+            let bit = (self.buffer >> (7 - self.bit_offset)) & 1;
+            self.bit_offset += 1;
+            value = (value << 1) | (bit as u64);
+        }
+
+        Ok(value)
+    }
+}
+
+// Writes bits MSB-first into a growable byte buffer, the inverse of `BitStreamReader`.
+struct BitStreamWriter {
+    data: Vec<u8>,
+    buffer: u8,
+    bit_offset: u8,
+}
+
+impl BitStreamWriter {
+    fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            buffer: 0,
+            bit_offset: 0,
+        }
+    }
+
+    // Pushes the low `bits` bits of `value` (at most 64) into the stream, most significant
+    // bit first, flushing the one-byte buffer into the output as it fills up.
+    fn write(&mut self, value: u64, bits: u32) {
+        for i in (0..bits).rev() {
+            let bit = ((value >> i) & 1) as u8;
+            self.buffer |= bit << (7 - self.bit_offset);
+            self.bit_offset += 1;
+
+            if self.bit_offset == 8 {
+                self.data.push(self.buffer);
+                self.buffer = 0;
+                self.bit_offset = 0;
+            }
+        }
+    }
+
+    // Flushes a partially filled trailing byte, zero-padding the unused low bits.
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_offset > 0 {
+            self.data.push(self.buffer);
+        }
+
+        self.data
+    }
+}